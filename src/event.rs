@@ -0,0 +1,9 @@
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    Info(String),
+    ServerSend(String),
+    TimedCommand(Duration, String),
+    ResumeCoroutine(Duration, u32),
+}
@@ -0,0 +1,7 @@
+pub const ALIAS_TABLE: &str = "alias_table";
+pub const TRIGGER_TABLE: &str = "trigger_table";
+pub const PROMPT_TRIGGER_TABLE: &str = "prompt_trigger_table";
+pub const GMCP_LISTENER_TABLE: &str = "gmcp_listener_table";
+
+pub const ON_CONNCTION_CALLBACK: &str = "_on_connection_callback";
+pub const ON_GMCP_READY_CALLBACK: &str = "_on_gmcp_ready_callback";
@@ -2,7 +2,8 @@ use super::constants::*;
 use super::user_data::*;
 use super::util::*;
 use crate::event::Event;
-use rlua::{Lua, Result as LuaResult};
+use mlua::{Lua, LuaSerdeExt, Result as LuaResult};
+use std::collections::HashMap;
 use std::io::prelude::*;
 use std::{error::Error, fs::File, result::Result, sync::mpsc::Sender};
 use strip_ansi_escapes::strip as strip_ansi;
@@ -10,170 +11,276 @@ use strip_ansi_escapes::strip as strip_ansi;
 pub struct LuaScript {
     state: Lua,
     writer: Sender<Event>,
+    coroutines: CoroutineRegistry,
+    current_plugin: CurrentPlugin,
+    plugins: HashMap<String, mlua::RegistryKey>,
 }
 
-fn create_default_lua_state(writer: Sender<Event>) -> Lua {
+fn create_default_lua_state(
+    writer: Sender<Event>,
+    coroutines: CoroutineRegistry,
+    current_plugin: CurrentPlugin,
+) -> Lua {
     let state = Lua::new();
 
-    let blight = BlightMud::new(writer);
-    state
-        .context(|ctx| -> LuaResult<()> {
-            let globals = ctx.globals();
-            globals.set("blight", blight)?;
-
-            let alias_table = ctx.create_table()?;
-            globals.set(ALIAS_TABLE, alias_table)?;
-            let trigger_table = ctx.create_table()?;
-            globals.set(TRIGGER_TABLE, trigger_table)?;
-            let prompt_trigger = ctx.create_table()?;
-            globals.set(PROMPT_TRIGGER_TABLE, prompt_trigger)?;
-            let gmcp_listener_table = ctx.create_table()?;
-            globals.set(GMCP_LISTENER_TABLE, gmcp_listener_table)?;
-
-            Ok(())
-        })
-        .unwrap();
+    let blight = BlightMud::new(writer, coroutines, current_plugin);
+    (|| -> LuaResult<()> {
+        state.globals().set("blight", blight)?;
+
+        // Kept out of globals (and thus out of reach of any plugin's sandboxed `_ENV`) by
+        // living in the Lua registry instead - the only way to reach them is the `blight`
+        // userdata API.
+        let alias_table = state.create_table()?;
+        state.set_named_registry_value(ALIAS_TABLE, alias_table)?;
+        let trigger_table = state.create_table()?;
+        state.set_named_registry_value(TRIGGER_TABLE, trigger_table)?;
+        let prompt_trigger = state.create_table()?;
+        state.set_named_registry_value(PROMPT_TRIGGER_TABLE, prompt_trigger)?;
+        let gmcp_listener_table = state.create_table()?;
+        state.set_named_registry_value(GMCP_LISTENER_TABLE, gmcp_listener_table)?;
+
+        Ok(())
+    })()
+    .unwrap();
     state
 }
 
 impl LuaScript {
     pub fn new(main_thread_writer: Sender<Event>) -> Self {
+        let coroutines = CoroutineRegistry::default();
+        let current_plugin = CurrentPlugin::default();
         Self {
-            state: create_default_lua_state(main_thread_writer.clone()),
+            state: create_default_lua_state(
+                main_thread_writer.clone(),
+                coroutines.clone(),
+                current_plugin.clone(),
+            ),
             writer: main_thread_writer,
+            coroutines,
+            current_plugin,
+            plugins: HashMap::new(),
         }
     }
 
     pub fn reset(&mut self) {
-        self.state = create_default_lua_state(self.writer.clone());
+        self.coroutines.borrow_mut().clear();
+        self.plugins.clear();
+        self.state = create_default_lua_state(
+            self.writer.clone(),
+            self.coroutines.clone(),
+            self.current_plugin.clone(),
+        );
+    }
+
+    /// Resume a coroutine previously parked in `blight.wait()`, driving it until it either
+    /// finishes or yields another wait duration (in which case a new `Event::ResumeCoroutine`
+    /// is scheduled for it).
+    pub fn resume_coroutine(&mut self, id: u32) {
+        let (key, owner) = match self.coroutines.borrow_mut().remove(&id) {
+            Some(entry) => entry,
+            None => return,
+        };
+        let thread: mlua::Thread = match self.state.registry_value(&key) {
+            Ok(thread) => thread,
+            Err(_) => return,
+        };
+        self.state.remove_registry_value(key).ok();
+        with_owner(&self.current_plugin, owner.clone(), || {
+            self.drive_coroutine(id, thread, owner)
+        });
+    }
+
+    fn drive_coroutine(&self, id: u32, thread: mlua::Thread, owner: Option<String>) {
+        match thread.resume::<_, Option<f64>>(()) {
+            Ok(Some(seconds)) if thread.status() == mlua::ThreadStatus::Resumable => {
+                if let Ok(key) = self.state.create_registry_value(thread) {
+                    self.coroutines.borrow_mut().insert(id, (key, owner));
+                    self.writer
+                        .send(Event::ResumeCoroutine(
+                            std::time::Duration::from_secs_f64(seconds),
+                            id,
+                        ))
+                        .ok();
+                }
+            }
+            Err(msg) => output_stack_trace(&self.writer, &msg.to_string()),
+            _ => {}
+        }
     }
 
     pub fn check_for_alias_match(&self, input: &str) -> bool {
         let mut response = false;
-        self.state.context(|ctx| {
-            let alias_table: rlua::Table = ctx.globals().get(ALIAS_TABLE).unwrap();
-            for pair in alias_table.pairs::<rlua::Value, rlua::AnyUserData>() {
-                let (_, alias) = pair.unwrap();
-                let rust_alias = &alias.borrow::<Alias>().unwrap();
-                let regex = &rust_alias.regex;
-                if rust_alias.enabled && regex.is_match(input) {
-                    let cb: rlua::Function = alias.get_user_value().unwrap();
-                    let captures: Vec<String> = regex
-                        .captures(input)
-                        .unwrap()
-                        .iter()
-                        .map(|c| match c {
-                            Some(m) => m.as_str().to_string(),
-                            None => String::new(),
-                        })
-                        .collect();
-                    if let Err(msg) = cb.call::<_, ()>(captures) {
-                        output_stack_trace(&self.writer, &msg.to_string());
-                    }
-                    response = true;
+        let alias_table: mlua::Table = self.state.named_registry_value(ALIAS_TABLE).unwrap();
+        for pair in alias_table.pairs::<mlua::Value, mlua::AnyUserData>() {
+            let (_, alias) = pair.unwrap();
+            let rust_alias = &alias.borrow::<Alias>().unwrap();
+            let regex = &rust_alias.regex;
+            if rust_alias.enabled && regex.is_match(input) {
+                let cb: mlua::Function = alias.user_value().unwrap();
+                let captures: Vec<String> = regex
+                    .captures(input)
+                    .unwrap()
+                    .iter()
+                    .map(|c| match c {
+                        Some(m) => m.as_str().to_string(),
+                        None => String::new(),
+                    })
+                    .collect();
+                let owner = rust_alias.owner.clone();
+                let result = with_owner(&self.current_plugin, owner, || cb.call::<_, ()>(captures));
+                if let Err(msg) = result {
+                    output_stack_trace(&self.writer, &msg.to_string());
                 }
+                response = true;
             }
-        });
+        }
         response
     }
 
-    pub fn check_for_trigger_match(&self, input: &str) -> bool {
+    pub fn check_for_trigger_match(&self, input: &str) -> TriggerEffect {
         self.check_trigger_match(input, TRIGGER_TABLE)
     }
 
-    pub fn check_for_prompt_trigger_match(&self, input: &str) -> bool {
+    pub fn check_for_prompt_trigger_match(&self, input: &str) -> TriggerEffect {
         self.check_trigger_match(input, PROMPT_TRIGGER_TABLE)
     }
 
-    fn check_trigger_match(&self, input: &str, table: &str) -> bool {
+    fn check_trigger_match(&self, input: &str, table: &str) -> TriggerEffect {
         let clean_bytes = strip_ansi(input.as_bytes()).unwrap();
-        let input = &String::from_utf8_lossy(&clean_bytes);
-        let mut response = false;
-        self.state.context(|ctx| {
-            let trigger_table: rlua::Table = ctx.globals().get(table).unwrap();
-            for pair in trigger_table.pairs::<rlua::Value, rlua::AnyUserData>() {
-                let (_, trigger) = pair.unwrap();
-                let rust_trigger = &trigger.borrow::<Trigger>().unwrap();
-                if rust_trigger.enabled && rust_trigger.regex.is_match(input) {
-                    let cb: rlua::Function = trigger.get_user_value().unwrap();
-                    let captures: Vec<String> = rust_trigger
-                        .regex
-                        .captures(input)
-                        .unwrap()
-                        .iter()
-                        .map(|c| match c {
-                            Some(m) => m.as_str().to_string(),
-                            None => String::new(),
-                        })
-                        .collect();
-                    if let Err(msg) = cb.call::<_, ()>(captures) {
-                        output_stack_trace(&self.writer, &msg.to_string());
-                    }
-                    response = rust_trigger.gag;
+        let stripped = String::from_utf8_lossy(&clean_bytes).to_string();
+        let mut effect = TriggerEffect::None;
+        let trigger_table: mlua::Table = self.state.named_registry_value(table).unwrap();
+        for pair in trigger_table.pairs::<mlua::Value, mlua::AnyUserData>() {
+            let (_, trigger) = pair.unwrap();
+            let rust_trigger = &trigger.borrow::<Trigger>().unwrap();
+            let haystack = if rust_trigger.raw { input } else { &stripped };
+            if rust_trigger.enabled && rust_trigger.regex.is_match(haystack) {
+                let cb: mlua::Function = trigger.user_value().unwrap();
+                let captures: Vec<String> = rust_trigger
+                    .regex
+                    .captures(haystack)
+                    .unwrap()
+                    .iter()
+                    .map(|c| match c {
+                        Some(m) => m.as_str().to_string(),
+                        None => String::new(),
+                    })
+                    .collect();
+                let owner = rust_trigger.owner.clone();
+                let result = with_owner(&self.current_plugin, owner, || {
+                    cb.call::<_, Option<String>>((captures, input.to_string()))
+                });
+                match result {
+                    Ok(Some(replacement)) => effect = TriggerEffect::Replace(replacement),
+                    Ok(None) if rust_trigger.gag => effect = TriggerEffect::Gag,
+                    Ok(None) => {}
+                    Err(msg) => output_stack_trace(&self.writer, &msg.to_string()),
                 }
             }
-        });
-        response
+        }
+        effect
     }
 
     pub fn receive_gmcp(&mut self, data: &str) {
-        let split = data
-            .splitn(2, ' ')
-            .map(String::from)
-            .collect::<Vec<String>>();
-        let msg_type = &split[0];
-        let content = &split[1];
-        self.state
-            .context(|ctx| {
-                let listener_table: rlua::Table = ctx.globals().get(GMCP_LISTENER_TABLE).unwrap();
-                if let Ok(func) = listener_table.get::<_, rlua::Function>(msg_type.clone()) {
-                    func.call::<_, ()>(content.clone())?;
-                }
-                rlua::Result::Ok(())
-            })
-            .ok();
+        let mut split = data.splitn(2, ' ');
+        let msg_type = split.next().unwrap_or(data);
+        let content = split.next().unwrap_or("");
+        let payload: mlua::Value = match serde_json::from_str::<serde_json::Value>(content) {
+            Ok(value) => self
+                .state
+                .to_value(&value)
+                .unwrap_or_else(|_| mlua::Value::String(self.state.create_string(content).unwrap())),
+            Err(_) => mlua::Value::String(self.state.create_string(content).unwrap()),
+        };
+        let listener_table: mlua::Table =
+            self.state.named_registry_value(GMCP_LISTENER_TABLE).unwrap();
+        if let Ok(listener) = listener_table.get::<_, mlua::AnyUserData>(msg_type) {
+            let rust_listener = &listener.borrow::<GmcpListener>().unwrap();
+            let cb: mlua::Function = listener.user_value().unwrap();
+            let owner = rust_listener.owner.clone();
+            let result = with_owner(&self.current_plugin, owner, || cb.call::<_, ()>(payload));
+            if let Err(msg) = result {
+                output_stack_trace(&self.writer, &msg.to_string());
+            }
+        }
     }
 
     pub fn load_script(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
         let mut file = File::open(path)?;
         let mut content = String::new();
         file.read_to_string(&mut content)?;
-        if let Err(msg) = self
+        if let Err(msg) = self.state.load(&content).set_name(path).exec() {
+            output_stack_trace(&self.writer, &msg.to_string());
+        }
+        Ok(())
+    }
+
+    /// Load a plugin file into its own sandboxed environment: a globals table that falls back
+    /// to the shared `blight`/registration API through `__index`, so two plugins defining the
+    /// same function or helper name can't clobber each other. The plugin is tracked by `path`
+    /// so it can later be reloaded or unloaded without touching other plugins' state.
+    pub fn load_plugin(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
+        let mut file = File::open(path)?;
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+
+        let env = self.state.create_table()?;
+        let meta = self.state.create_table()?;
+        meta.set("__index", self.state.globals())?;
+        env.set_metatable(Some(meta));
+
+        *self.current_plugin.borrow_mut() = Some(path.to_string());
+        let result = self
             .state
-            .context(|ctx| -> LuaResult<()> { ctx.load(&content).set_name(path)?.exec() })
-        {
+            .load(&content)
+            .set_name(path)
+            .set_environment(env.clone())
+            .exec();
+        *self.current_plugin.borrow_mut() = None;
+
+        if let Err(msg) = result {
             output_stack_trace(&self.writer, &msg.to_string());
         }
+
+        let key = self.state.create_registry_value(env)?;
+        self.plugins.insert(path.to_string(), key);
         Ok(())
     }
 
+    /// Drop a plugin's environment and clear every alias/trigger/prompt-trigger it registered.
+    pub fn unload_plugin(&mut self, path: &str) {
+        if let Some(key) = self.plugins.remove(path) {
+            self.state.remove_registry_value(key).ok();
+        }
+        purge_plugin_rules(&self.state, path).ok();
+    }
+
+    pub fn reload_plugin(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
+        self.unload_plugin(path);
+        self.load_plugin(path)
+    }
+
     pub fn on_connect(&mut self) {
-        self.state
-            .context(|ctx| -> Result<(), rlua::Error> {
-                if let Ok(callback) = ctx
-                    .globals()
-                    .get::<_, rlua::Function>(ON_CONNCTION_CALLBACK)
-                {
-                    callback.call::<_, ()>(())
-                } else {
-                    Ok(())
-                }
-            })
-            .unwrap();
+        if let Ok(callback) = self
+            .state
+            .globals()
+            .get::<_, mlua::Function>(ON_CONNCTION_CALLBACK)
+        {
+            with_owner(&self.current_plugin, None, || {
+                callback.call::<_, ()>(()).unwrap()
+            });
+        }
     }
 
     pub fn on_gmcp_ready(&mut self) {
-        self.state
-            .context(|ctx| -> Result<(), rlua::Error> {
-                if let Ok(callback) = ctx
-                    .globals()
-                    .get::<_, rlua::Function>(ON_GMCP_READY_CALLBACK)
-                {
-                    callback.call::<_, ()>(())
-                } else {
-                    Ok(())
-                }
-            })
-            .unwrap();
+        if let Ok(callback) = self
+            .state
+            .globals()
+            .get::<_, mlua::Function>(ON_GMCP_READY_CALLBACK)
+        {
+            with_owner(&self.current_plugin, None, || {
+                callback.call::<_, ()>(()).unwrap()
+            });
+        }
     }
-}
\ No newline at end of file
+}
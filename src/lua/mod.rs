@@ -0,0 +1,6 @@
+mod constants;
+mod lua_script;
+mod user_data;
+mod util;
+
+pub use lua_script::LuaScript;
@@ -0,0 +1,302 @@
+use super::constants::*;
+use super::util::output_stack_trace;
+use crate::event::Event;
+use mlua::{AnyUserData, Lua, RegistryKey, Result as LuaResult, Table, UserData, UserDataMethods};
+use regex::Regex;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        mpsc::Sender,
+    },
+    time::Duration,
+};
+
+/// Parked coroutines keyed by id, alongside the plugin (if any) that spawned them so a resume
+/// can be run back in that plugin's ownership context.
+pub type CoroutineRegistry = Rc<RefCell<HashMap<u32, (RegistryKey, Option<String>)>>>;
+/// Path of the plugin whose code is currently on the call stack, if any. Set by
+/// `LuaScript::load_plugin` for the duration of the chunk's top-level execution, and re-applied
+/// around any callback (alias/trigger match, GMCP listener, resumed coroutine) that plugin
+/// registered, so rules it adds from inside those callbacks are still tied back to it.
+pub type CurrentPlugin = Rc<RefCell<Option<String>>>;
+
+/// Run `f` with `current_plugin` temporarily set to `owner`, restoring whatever it held before
+/// regardless of nesting.
+pub fn with_owner<R>(current_plugin: &CurrentPlugin, owner: Option<String>, f: impl FnOnce() -> R) -> R {
+    let previous = current_plugin.replace(owner);
+    let result = f();
+    *current_plugin.borrow_mut() = previous;
+    result
+}
+
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+fn next_id() -> u32 {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+pub struct Alias {
+    pub regex: Regex,
+    pub enabled: bool,
+    pub owner: Option<String>,
+}
+
+impl UserData for Alias {}
+
+pub struct Trigger {
+    pub regex: Regex,
+    pub enabled: bool,
+    pub gag: bool,
+    pub raw: bool,
+    pub owner: Option<String>,
+}
+
+impl UserData for Trigger {}
+
+/// Outcome of running a line through a trigger table: leave it untouched, drop it, or
+/// substitute it with a script-supplied replacement.
+pub enum TriggerEffect {
+    None,
+    Gag,
+    Replace(String),
+}
+
+pub struct GmcpListener {
+    pub owner: Option<String>,
+}
+
+impl UserData for GmcpListener {}
+
+fn trigger_options(options: Option<Table>) -> LuaResult<(bool, bool)> {
+    let gag = options
+        .as_ref()
+        .and_then(|o| o.get("gag").ok())
+        .unwrap_or(false);
+    let raw = options
+        .as_ref()
+        .and_then(|o| o.get("raw").ok())
+        .unwrap_or(false);
+    Ok((gag, raw))
+}
+
+fn create_rule<T: UserData + Send + 'static>(
+    lua: &Lua,
+    table_name: &str,
+    rule: T,
+    cb: mlua::Function,
+) -> LuaResult<u32> {
+    let table: Table = lua.named_registry_value(table_name)?;
+    let id = next_id();
+    let rule = lua.create_userdata(rule)?;
+    rule.set_user_value(cb)?;
+    table.set(id, rule)?;
+    Ok(id)
+}
+
+fn remove_rule(lua: &Lua, table_name: &str, id: u32) -> LuaResult<()> {
+    let table: Table = lua.named_registry_value(table_name)?;
+    table.set(id, mlua::Value::Nil)
+}
+
+fn set_alias_enabled(lua: &Lua, id: u32, enabled: bool) -> LuaResult<()> {
+    let table: Table = lua.named_registry_value(ALIAS_TABLE)?;
+    let alias: AnyUserData = table.get(id)?;
+    alias.borrow_mut::<Alias>()?.enabled = enabled;
+    Ok(())
+}
+
+fn set_trigger_enabled(lua: &Lua, table_name: &str, id: u32, enabled: bool) -> LuaResult<()> {
+    let table: Table = lua.named_registry_value(table_name)?;
+    let trigger: AnyUserData = table.get(id)?;
+    trigger.borrow_mut::<Trigger>()?.enabled = enabled;
+    Ok(())
+}
+
+fn purge_owned<T: UserData + 'static>(
+    lua: &Lua,
+    table_name: &str,
+    is_owned: impl Fn(&T) -> bool,
+) -> LuaResult<()> {
+    let table: Table = lua.named_registry_value(table_name)?;
+    let mut stale = Vec::new();
+    for pair in table.clone().pairs::<mlua::Value, AnyUserData>() {
+        let (key, rule) = pair?;
+        if is_owned(&*rule.borrow::<T>()?) {
+            stale.push(key);
+        }
+    }
+    for key in stale {
+        table.set(key, mlua::Value::Nil)?;
+    }
+    Ok(())
+}
+
+/// Remove every alias/trigger/prompt-trigger registered by the plugin at `path`. Used when a
+/// plugin is unloaded or reloaded so it doesn't leave rules running with stale state.
+pub fn purge_plugin_rules(lua: &Lua, path: &str) -> LuaResult<()> {
+    purge_owned::<Alias>(lua, ALIAS_TABLE, |a| a.owner.as_deref() == Some(path))?;
+    purge_owned::<Trigger>(lua, TRIGGER_TABLE, |t| t.owner.as_deref() == Some(path))?;
+    purge_owned::<Trigger>(lua, PROMPT_TRIGGER_TABLE, |t| {
+        t.owner.as_deref() == Some(path)
+    })?;
+    Ok(())
+}
+
+pub struct BlightMud {
+    writer: Sender<Event>,
+    coroutines: CoroutineRegistry,
+    current_plugin: CurrentPlugin,
+}
+
+impl BlightMud {
+    pub fn new(writer: Sender<Event>, coroutines: CoroutineRegistry, current_plugin: CurrentPlugin) -> Self {
+        Self {
+            writer,
+            coroutines,
+            current_plugin,
+        }
+    }
+}
+
+impl UserData for BlightMud {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method(
+            "add_alias",
+            |lua, this, (regex, cb): (String, mlua::Function)| {
+                let regex =
+                    Regex::new(&regex).map_err(|e| mlua::Error::RuntimeError(e.to_string()))?;
+                create_rule(
+                    lua,
+                    ALIAS_TABLE,
+                    Alias {
+                        regex,
+                        enabled: true,
+                        owner: this.current_plugin.borrow().clone(),
+                    },
+                    cb,
+                )
+            },
+        );
+        methods.add_method("remove_alias", |lua, _this, id: u32| {
+            remove_rule(lua, ALIAS_TABLE, id)
+        });
+        methods.add_method(
+            "set_alias_enabled",
+            |lua, _this, (id, enabled): (u32, bool)| set_alias_enabled(lua, id, enabled),
+        );
+
+        methods.add_method(
+            "add_trigger",
+            |lua, this, (regex, options, cb): (String, Option<Table>, mlua::Function)| {
+                let regex =
+                    Regex::new(&regex).map_err(|e| mlua::Error::RuntimeError(e.to_string()))?;
+                let (gag, raw) = trigger_options(options)?;
+                create_rule(
+                    lua,
+                    TRIGGER_TABLE,
+                    Trigger {
+                        regex,
+                        enabled: true,
+                        gag,
+                        raw,
+                        owner: this.current_plugin.borrow().clone(),
+                    },
+                    cb,
+                )
+            },
+        );
+        methods.add_method("remove_trigger", |lua, _this, id: u32| {
+            remove_rule(lua, TRIGGER_TABLE, id)
+        });
+        methods.add_method(
+            "set_trigger_enabled",
+            |lua, _this, (id, enabled): (u32, bool)| {
+                set_trigger_enabled(lua, TRIGGER_TABLE, id, enabled)
+            },
+        );
+
+        methods.add_method(
+            "add_prompt_trigger",
+            |lua, this, (regex, options, cb): (String, Option<Table>, mlua::Function)| {
+                let regex =
+                    Regex::new(&regex).map_err(|e| mlua::Error::RuntimeError(e.to_string()))?;
+                let (gag, raw) = trigger_options(options)?;
+                create_rule(
+                    lua,
+                    PROMPT_TRIGGER_TABLE,
+                    Trigger {
+                        regex,
+                        enabled: true,
+                        gag,
+                        raw,
+                        owner: this.current_plugin.borrow().clone(),
+                    },
+                    cb,
+                )
+            },
+        );
+        methods.add_method("remove_prompt_trigger", |lua, _this, id: u32| {
+            remove_rule(lua, PROMPT_TRIGGER_TABLE, id)
+        });
+        methods.add_method(
+            "set_prompt_trigger_enabled",
+            |lua, _this, (id, enabled): (u32, bool)| {
+                set_trigger_enabled(lua, PROMPT_TRIGGER_TABLE, id, enabled)
+            },
+        );
+
+        methods.add_method(
+            "add_gmcp_listener",
+            |lua, this, (msg_type, cb): (String, mlua::Function)| {
+                let table: Table = lua.named_registry_value(GMCP_LISTENER_TABLE)?;
+                let listener = lua.create_userdata(GmcpListener {
+                    owner: this.current_plugin.borrow().clone(),
+                })?;
+                listener.set_user_value(cb)?;
+                table.set(msg_type, listener)
+            },
+        );
+
+        methods.add_method("send", |_lua, this, cmd: String| {
+            this.writer
+                .send(Event::ServerSend(cmd))
+                .map_err(|e| mlua::Error::RuntimeError(e.to_string()))
+        });
+
+        methods.add_method("send_after", |_lua, this, (seconds, cmd): (f64, String)| {
+            this.writer
+                .send(Event::TimedCommand(Duration::from_secs_f64(seconds), cmd))
+                .map_err(|e| mlua::Error::RuntimeError(e.to_string()))
+        });
+
+        methods.add_method("wait", |lua, _this, seconds: f64| -> LuaResult<()> {
+            let coroutine: Table = lua.globals().get("coroutine")?;
+            let yield_fn: mlua::Function = coroutine.get("yield")?;
+            yield_fn.call(seconds)
+        });
+
+        methods.add_method("spawn", |lua, this, func: mlua::Function| {
+            let thread = lua.create_thread(func)?;
+            let id = next_id();
+            match thread.resume::<_, Option<f64>>(()) {
+                Ok(Some(seconds)) if thread.status() == mlua::ThreadStatus::Resumable => {
+                    let owner = this.current_plugin.borrow().clone();
+                    let key = lua.create_registry_value(thread)?;
+                    this.coroutines.borrow_mut().insert(id, (key, owner));
+                    this.writer
+                        .send(Event::ResumeCoroutine(
+                            Duration::from_secs_f64(seconds),
+                            id,
+                        ))
+                        .map_err(|e| mlua::Error::RuntimeError(e.to_string()))?;
+                }
+                Err(msg) => output_stack_trace(&this.writer, &msg.to_string()),
+                _ => {}
+            }
+            Ok(id)
+        });
+    }
+}
@@ -0,0 +1,8 @@
+use crate::event::Event;
+use std::sync::mpsc::Sender;
+
+pub fn output_stack_trace(writer: &Sender<Event>, msg: &str) {
+    for line in msg.lines() {
+        writer.send(Event::Info(format!("[Lua] {}", line))).ok();
+    }
+}